@@ -0,0 +1,129 @@
+use fuser::{FileAttr, FileType};
+use libc::{O_ACCMODE, O_RDONLY, O_RDWR, O_TRUNC, R_OK, W_OK, X_OK};
+
+use crate::acl::Acl;
+
+const S_ISVTX: u16 = 0o1000;
+
+/// Whether `uid`/`gid` may access an entry with `attr` and access ACL `acl`
+/// as asked by `mask`, a combination of `R_OK`, `W_OK` and `X_OK`
+///
+/// Only the primary group of the caller is known, so access granted through
+/// a supplementary group is denied.
+pub fn allowed(attr: &FileAttr, acl: Option<&Acl>, uid: u32, gid: u32, mask: i32) -> bool {
+    let mask = (mask & (R_OK | W_OK | X_OK)) as u16;
+    if uid == 0 {
+        // Root can do anything but execute files nobody can execute
+        let executable = attr.kind == FileType::Directory || attr.perm & 0o111 != 0;
+        return mask & X_OK as u16 == 0 || executable;
+    }
+    if let Some(acl) = acl {
+        return acl.allows(attr, uid, gid, mask);
+    }
+
+    let granted = if uid == attr.uid {
+        attr.perm >> 6
+    } else if gid == attr.gid {
+        attr.perm >> 3
+    } else {
+        attr.perm
+    };
+    granted & mask == mask
+}
+
+/// Whether `uid` may remove or rename `entry` from the directory `dir`, only
+/// owners are allowed to in sticky directories such as `/tmp`
+pub fn may_delete(dir: &FileAttr, entry: &FileAttr, uid: u32) -> bool {
+    dir.perm & S_ISVTX == 0 || uid == 0 || uid == dir.uid || uid == entry.uid
+}
+
+/// Access `open` needs for `flags`
+pub fn open_mask(flags: i32) -> i32 {
+    let mask = match flags & O_ACCMODE {
+        O_RDONLY => R_OK,
+        O_RDWR => R_OK | W_OK,
+        _ => W_OK,
+    };
+    match flags & O_TRUNC != 0 {
+        true => mask | W_OK,
+        false => mask,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use libc::{O_CREAT, O_WRONLY};
+    use std::time::UNIX_EPOCH;
+
+    pub fn attr(kind: FileType, perm: u16, uid: u32, gid: u32) -> FileAttr {
+        FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind,
+            perm,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 4096,
+        }
+    }
+
+    #[test]
+    fn permission_bits_of_owner_group_and_others() {
+        let file = attr(FileType::RegularFile, 0o640, 1000, 100);
+        assert!(allowed(&file, None, 1000, 1, R_OK | W_OK));
+        assert!(!allowed(&file, None, 1000, 1, X_OK));
+        assert!(allowed(&file, None, 1001, 100, R_OK));
+        assert!(!allowed(&file, None, 1001, 100, W_OK));
+        assert!(!allowed(&file, None, 1001, 1, R_OK));
+    }
+
+    #[test]
+    fn only_the_first_matching_class_counts() {
+        // The owner is denied what the group and others are granted
+        let file = attr(FileType::RegularFile, 0o077, 1000, 100);
+        assert!(!allowed(&file, None, 1000, 100, R_OK));
+        assert!(allowed(&file, None, 1001, 100, R_OK));
+    }
+
+    #[test]
+    fn root_only_needs_someone_to_execute() {
+        let file = attr(FileType::RegularFile, 0o000, 1000, 100);
+        assert!(allowed(&file, None, 0, 0, R_OK | W_OK));
+        assert!(!allowed(&file, None, 0, 0, X_OK));
+        let script = attr(FileType::RegularFile, 0o010, 1000, 100);
+        assert!(allowed(&script, None, 0, 0, X_OK));
+        let dir = attr(FileType::Directory, 0o000, 1000, 100);
+        assert!(allowed(&dir, None, 0, 0, R_OK | W_OK | X_OK));
+    }
+
+    #[test]
+    fn sticky_directories_only_let_owners_delete() {
+        let tmp = attr(FileType::Directory, 0o1777, 0, 0);
+        let file = attr(FileType::RegularFile, 0o644, 1000, 100);
+        assert!(may_delete(&tmp, &file, 1000));
+        assert!(may_delete(&tmp, &file, 0));
+        assert!(!may_delete(&tmp, &file, 1001));
+
+        let owned = attr(FileType::Directory, 0o1777, 1001, 100);
+        assert!(may_delete(&owned, &file, 1001));
+        let plain = attr(FileType::Directory, 0o777, 0, 0);
+        assert!(may_delete(&plain, &file, 1001));
+    }
+
+    #[test]
+    fn open_needs_the_access_mode() {
+        assert_eq!(open_mask(O_RDONLY), R_OK);
+        assert_eq!(open_mask(O_WRONLY | O_CREAT), W_OK);
+        assert_eq!(open_mask(O_RDWR), R_OK | W_OK);
+        assert_eq!(open_mask(O_RDONLY | O_TRUNC), R_OK | W_OK);
+    }
+}
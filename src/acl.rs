@@ -0,0 +1,359 @@
+use fuser::FileAttr;
+
+/// Attribute holding the ACL checked when accessing an entry
+pub const ACCESS: &str = "system.posix_acl_access";
+/// Attribute holding the ACL entries created in a directory start from
+pub const DEFAULT: &str = "system.posix_acl_default";
+
+// Layout of the attributes as Linux passes them, see posix_acl_xattr.h
+const VERSION: u32 = 2;
+const HEADER_SIZE: usize = 4;
+const ENTRY_SIZE: usize = 8;
+
+const USER_OBJ: u16 = 0x01;
+const USER: u16 = 0x02;
+const GROUP_OBJ: u16 = 0x04;
+const GROUP: u16 = 0x08;
+const MASK: u16 = 0x10;
+const OTHER: u16 = 0x20;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Entry {
+    tag: u16,
+    perm: u16,
+    id: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Acl {
+    entries: Vec<Entry>,
+}
+
+impl Acl {
+    /// Decode an attribute value, None unless it is a well-formed ACL
+    pub fn parse(value: &[u8]) -> Option<Acl> {
+        if value.len() < HEADER_SIZE {
+            return None;
+        }
+        if u32::from_le_bytes(value[..HEADER_SIZE].try_into().unwrap()) != VERSION {
+            return None;
+        }
+        let chunks = value[HEADER_SIZE..].chunks_exact(ENTRY_SIZE);
+        if !chunks.remainder().is_empty() {
+            return None;
+        }
+        let entries = chunks
+            .map(|chunk| Entry {
+                tag: u16::from_le_bytes([chunk[0], chunk[1]]),
+                perm: u16::from_le_bytes([chunk[2], chunk[3]]),
+                id: u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]),
+            })
+            .collect();
+        let acl = Acl { entries };
+        acl.is_valid().then_some(acl)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut value = VERSION.to_le_bytes().to_vec();
+        for entry in &self.entries {
+            value.extend(entry.tag.to_le_bytes());
+            value.extend(entry.perm.to_le_bytes());
+            value.extend(entry.id.to_le_bytes());
+        }
+        value
+    }
+
+    // One owner, owning group and other entry, a mask as soon as there are
+    // named users or groups, and no name twice
+    fn is_valid(&self) -> bool {
+        let count = |tag| self.entries.iter().filter(|entry| entry.tag == tag).count();
+        let named = |tag| {
+            let mut ids: Vec<u32> = self
+                .entries
+                .iter()
+                .filter(|entry| entry.tag == tag)
+                .map(|entry| entry.id)
+                .collect();
+            ids.sort_unstable();
+            ids.dedup();
+            ids.len() == count(tag)
+        };
+        self.entries.iter().all(|entry| {
+            entry.perm <= 0o7
+                && [USER_OBJ, USER, GROUP_OBJ, GROUP, MASK, OTHER].contains(&entry.tag)
+        }) && count(USER_OBJ) == 1
+            && count(GROUP_OBJ) == 1
+            && count(OTHER) == 1
+            && count(MASK) <= 1
+            && (count(MASK) == 1 || count(USER) + count(GROUP) == 0)
+            && named(USER)
+            && named(GROUP)
+    }
+
+    /// Whether the ACL says nothing the permission bits don't
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    /// Permission bits matching the ACL, the group bits show the mask if any
+    pub fn mode(&self) -> u16 {
+        let group = self.perm(MASK).unwrap_or(self.perm(GROUP_OBJ).unwrap_or(0));
+        self.perm(USER_OBJ).unwrap_or(0) << 6 | group << 3 | self.perm(OTHER).unwrap_or(0)
+    }
+
+    /// Carry the permission bits of a chmod over to the entries they show
+    pub fn set_mode(&mut self, mode: u16) {
+        let has_mask = self.perm(MASK).is_some();
+        for entry in self.entries.iter_mut() {
+            match entry.tag {
+                USER_OBJ => entry.perm = mode >> 6 & 0o7,
+                MASK => entry.perm = mode >> 3 & 0o7,
+                GROUP_OBJ if !has_mask => entry.perm = mode >> 3 & 0o7,
+                OTHER => entry.perm = mode & 0o7,
+                _ => {}
+            }
+        }
+    }
+
+    /// Access ACL of an entry created with `mode` in a directory with this
+    /// default ACL, which takes the place of the umask
+    pub fn inherit(&self, mode: u16) -> Acl {
+        let mut acl = self.clone();
+        let has_mask = acl.perm(MASK).is_some();
+        for entry in acl.entries.iter_mut() {
+            match entry.tag {
+                USER_OBJ => entry.perm &= mode >> 6 & 0o7,
+                MASK => entry.perm &= mode >> 3 & 0o7,
+                GROUP_OBJ if !has_mask => entry.perm &= mode >> 3 & 0o7,
+                OTHER => entry.perm &= mode & 0o7,
+                _ => {}
+            }
+        }
+        acl
+    }
+
+    /// Whether `uid`/`gid` get the `R_OK`/`W_OK`/`X_OK` bits of `mask` on an
+    /// entry with `attr`, following the POSIX.1e access check algorithm
+    pub fn allows(&self, attr: &FileAttr, uid: u32, gid: u32, mask: u16) -> bool {
+        let granted = |perm: u16| perm & mask == mask;
+        if uid == attr.uid {
+            return self.perm(USER_OBJ).is_some_and(granted);
+        }
+
+        // Named users and all groups are limited by the mask
+        let limit = self.perm(MASK).unwrap_or(0o7);
+        if let Some(entry) = self.find(USER, uid) {
+            return granted(entry.perm & limit);
+        }
+        let groups: Vec<u16> = self
+            .entries
+            .iter()
+            .filter(|entry| {
+                (entry.tag == GROUP_OBJ && gid == attr.gid)
+                    || (entry.tag == GROUP && entry.id == gid)
+            })
+            .map(|entry| entry.perm & limit)
+            .collect();
+        if !groups.is_empty() {
+            return groups.into_iter().any(granted);
+        }
+        self.perm(OTHER).is_some_and(granted)
+    }
+
+    fn perm(&self, tag: u16) -> Option<u16> {
+        self.entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map(|entry| entry.perm)
+    }
+
+    fn find(&self, tag: u16, id: u32) -> Option<&Entry> {
+        self.entries
+            .iter()
+            .find(|entry| entry.tag == tag && entry.id == id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::{self, tests::attr};
+    use fuser::FileType;
+    use libc::{R_OK, W_OK, X_OK};
+
+    const R: u16 = R_OK as u16;
+    const W: u16 = W_OK as u16;
+    const X: u16 = X_OK as u16;
+
+    fn blob(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut value = VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            value.extend(tag.to_le_bytes());
+            value.extend(perm.to_le_bytes());
+            value.extend(id.to_le_bytes());
+        }
+        value
+    }
+
+    fn minimal() -> Vec<u8> {
+        blob(&[(USER_OBJ, 6, 0), (GROUP_OBJ, 4, 0), (OTHER, 0, 0)])
+    }
+
+    // user::rw-, user:2000:rwx, group::r-x, group:300:rw-, mask::r--, other::---
+    fn extended() -> Vec<u8> {
+        blob(&[
+            (USER_OBJ, 6, 0),
+            (USER, 7, 2000),
+            (GROUP_OBJ, 5, 0),
+            (GROUP, 6, 300),
+            (MASK, 4, 0),
+            (OTHER, 0, 0),
+        ])
+    }
+
+    #[test]
+    fn minimal_acl_matches_the_permission_bits() {
+        let acl = Acl::parse(&minimal()).unwrap();
+        assert!(acl.is_minimal());
+        assert_eq!(acl.mode(), 0o640);
+        assert_eq!(acl.to_bytes(), minimal());
+    }
+
+    #[test]
+    fn extended_acl_shows_the_mask_as_group_bits() {
+        let acl = Acl::parse(&extended()).unwrap();
+        assert!(!acl.is_minimal());
+        assert_eq!(acl.mode(), 0o640);
+        assert_eq!(acl.to_bytes(), extended());
+    }
+
+    #[test]
+    fn chmod_changes_the_mask_rather_than_the_owning_group() {
+        let mut acl = Acl::parse(&extended()).unwrap();
+        acl.set_mode(0o750);
+        assert_eq!(acl.mode(), 0o750);
+        assert_eq!(acl.perm(GROUP_OBJ), Some(5));
+        assert_eq!(acl.perm(MASK), Some(5));
+
+        let mut acl = Acl::parse(&minimal()).unwrap();
+        acl.set_mode(0o705);
+        assert_eq!(acl.perm(GROUP_OBJ), Some(0));
+        assert_eq!(acl.mode(), 0o705);
+    }
+
+    #[test]
+    fn malformed_values_are_rejected() {
+        let truncated = &extended()[..extended().len() - 3];
+        assert_eq!(Acl::parse(truncated), None);
+        assert_eq!(Acl::parse(&[2, 0]), None);
+        assert_eq!(Acl::parse(&[]), None);
+
+        let mut version = minimal();
+        version[0] = 1;
+        assert_eq!(Acl::parse(&version), None);
+
+        // Missing other entry
+        assert_eq!(
+            Acl::parse(&blob(&[(USER_OBJ, 6, 0), (GROUP_OBJ, 4, 0)])),
+            None
+        );
+        // Named user without a mask
+        let unmasked = blob(&[
+            (USER_OBJ, 6, 0),
+            (USER, 6, 2000),
+            (GROUP_OBJ, 4, 0),
+            (OTHER, 0, 0),
+        ]);
+        assert_eq!(Acl::parse(&unmasked), None);
+        // Same user twice
+        let twice = blob(&[
+            (USER_OBJ, 6, 0),
+            (USER, 6, 2000),
+            (USER, 4, 2000),
+            (GROUP_OBJ, 4, 0),
+            (MASK, 6, 0),
+            (OTHER, 0, 0),
+        ]);
+        assert_eq!(Acl::parse(&twice), None);
+        // Unknown tag and out of range permission
+        let unknown = blob(&[
+            (USER_OBJ, 6, 0),
+            (GROUP_OBJ, 4, 0),
+            (OTHER, 0, 0),
+            (0x40, 0, 0),
+        ]);
+        assert_eq!(Acl::parse(&unknown), None);
+        assert_eq!(
+            Acl::parse(&blob(&[(USER_OBJ, 8, 0), (GROUP_OBJ, 4, 0), (OTHER, 0, 0)])),
+            None
+        );
+    }
+
+    #[test]
+    fn access_follows_the_first_matching_entry() {
+        let acl = Acl::parse(&extended()).unwrap();
+        let file = attr(FileType::RegularFile, acl.mode(), 1000, 100);
+
+        // Owner, not limited by the mask
+        assert!(acl.allows(&file, 1000, 100, R | W));
+        assert!(!acl.allows(&file, 1000, 100, X));
+        // Named user, limited by the mask
+        assert!(acl.allows(&file, 2000, 1, R));
+        assert!(!acl.allows(&file, 2000, 1, W));
+        // Owning and named groups, limited by the mask
+        assert!(acl.allows(&file, 1001, 100, R));
+        assert!(!acl.allows(&file, 1001, 100, X));
+        assert!(acl.allows(&file, 1001, 300, R));
+        assert!(!acl.allows(&file, 1001, 300, W));
+        // A matching group denies rather than falling back to other
+        let open_other = blob(&[
+            (USER_OBJ, 6, 0),
+            (GROUP_OBJ, 0, 0),
+            (MASK, 7, 0),
+            (OTHER, 4, 0),
+        ]);
+        let acl = Acl::parse(&open_other).unwrap();
+        assert!(!acl.allows(&file, 1001, 100, R));
+        assert!(acl.allows(&file, 1001, 1, R));
+    }
+
+    #[test]
+    fn root_ignores_the_acl_but_needs_someone_to_execute() {
+        let acl = Acl::parse(&extended()).unwrap();
+        let file = attr(FileType::RegularFile, acl.mode(), 1000, 100);
+        assert!(access::allowed(&file, Some(&acl), 0, 0, R_OK | W_OK));
+        assert!(!access::allowed(&file, Some(&acl), 0, 0, X_OK));
+        assert!(!access::allowed(&file, Some(&acl), 2000, 1, W_OK));
+
+        let script = attr(FileType::RegularFile, 0o700, 1000, 100);
+        assert!(access::allowed(&script, Some(&acl), 0, 0, X_OK));
+    }
+
+    #[test]
+    fn inherited_acl_is_limited_by_the_creation_mode() {
+        // default: user::rwx, user:2000:rwx, group::r-x, mask::rwx, other::r-x
+        let default = Acl::parse(&blob(&[
+            (USER_OBJ, 7, 0),
+            (USER, 7, 2000),
+            (GROUP_OBJ, 5, 0),
+            (MASK, 7, 0),
+            (OTHER, 5, 0),
+        ]))
+        .unwrap();
+
+        let file = default.inherit(0o666);
+        assert_eq!(file.mode(), 0o664);
+        assert_eq!(file.perm(MASK), Some(6));
+        // Named entries are only limited through the mask
+        assert_eq!(file.find(USER, 2000).unwrap().perm, 7);
+        assert_eq!(file.perm(GROUP_OBJ), Some(5));
+
+        let dir = default.inherit(0o777);
+        assert_eq!(dir.mode(), 0o775);
+        assert_eq!(dir, default);
+
+        let minimal = Acl::parse(&minimal()).unwrap().inherit(0o600);
+        assert_eq!(minimal.mode(), 0o600);
+        assert!(minimal.is_minimal());
+    }
+}
@@ -1,4 +1,5 @@
 use clap::Parser;
+use fuser::MountOption;
 use opendal::Scheme;
 use std::{collections::HashMap, str::FromStr};
 
@@ -14,6 +15,44 @@ pub struct App {
     /// Configuration of the OpenDAL scheme in the format <key1>=<val1>,<key2>=<val2>,..
     #[arg(short, long, value_parser = parse_options)]
     pub options: Option<HashMap<String, String>>,
+
+    /// FUSE mount option such as allow_other, auto_unmount, default_permissions or fsname=<name>, can be repeated
+    #[arg(long = "mount-option", value_parser = parse_mount_option)]
+    pub mount_options: Vec<MountOption>,
+
+    /// Mount read-only, rejecting every modification with EROFS
+    #[arg(long)]
+    pub read_only: bool,
+
+    /// Support hard links, moving the content of linked files to a hidden
+    /// `.dalfs-links` directory at the root of the backend
+    #[arg(long)]
+    pub hard_links: bool,
+
+    /// Validity in seconds of the URLs read from the user.dalfs.presign_read
+    /// attribute
+    #[arg(long, default_value_t = 3600)]
+    pub presign_expiry: u64,
+
+    /// Owner of all files, defaults to the mounting user
+    #[arg(long, default_value_t = current_uid())]
+    pub uid: u32,
+
+    /// Group of all files, defaults to the mounting user's group
+    #[arg(long, default_value_t = current_gid())]
+    pub gid: u32,
+
+    /// Permission bits (octal) removed from both --dir-mode and --file-mode
+    #[arg(long, value_parser = parse_mode, default_value = "022")]
+    pub umask: u16,
+
+    /// Permission bits (octal) of directories
+    #[arg(long, value_parser = parse_mode, default_value = "777")]
+    pub dir_mode: u16,
+
+    /// Permission bits (octal) of files
+    #[arg(long, value_parser = parse_mode, default_value = "666")]
+    pub file_mode: u16,
 }
 
 fn parse_options(raw: &str) -> Result<HashMap<String, String>, String> {
@@ -21,11 +60,114 @@ fn parse_options(raw: &str) -> Result<HashMap<String, String>, String> {
         .map(|kv| {
             kv.split_once('=')
                 .map(|(k, v)| (k.to_string(), v.to_string()))
-                .ok_or(String::from("Invalid key value format".to_string()))
+                .ok_or("Invalid key value format".to_string())
         })
         .collect::<Result<HashMap<String, String>, String>>()
 }
 
+// Same names as `mount.fuse`, anything unknown is passed to the kernel as is
+fn parse_mount_option(raw: &str) -> Result<MountOption, String> {
+    let option = match raw {
+        "auto_unmount" => MountOption::AutoUnmount,
+        "allow_other" => MountOption::AllowOther,
+        "allow_root" => MountOption::AllowRoot,
+        "default_permissions" => MountOption::DefaultPermissions,
+        "dev" => MountOption::Dev,
+        "nodev" => MountOption::NoDev,
+        "suid" => MountOption::Suid,
+        "nosuid" => MountOption::NoSuid,
+        "ro" => MountOption::RO,
+        "rw" => MountOption::RW,
+        "exec" => MountOption::Exec,
+        "noexec" => MountOption::NoExec,
+        "atime" => MountOption::Atime,
+        "noatime" => MountOption::NoAtime,
+        "dirsync" => MountOption::DirSync,
+        "sync" => MountOption::Sync,
+        "async" => MountOption::Async,
+        "" => return Err("Empty mount option".to_string()),
+        _ => match raw.split_once('=') {
+            Some(("fsname", name)) => MountOption::FSName(name.to_string()),
+            Some(("subtype", name)) => MountOption::Subtype(name.to_string()),
+            _ => MountOption::CUSTOM(raw.to_string()),
+        },
+    };
+    Ok(option)
+}
+
 fn parse_type(raw: &str) -> Result<Scheme, String> {
     Scheme::from_str(raw).map_err(|_| "Invalid OpenDAL scheme".to_string())
 }
+
+fn parse_mode(raw: &str) -> Result<u16, String> {
+    u16::from_str_radix(raw.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or("Invalid octal permission bits".to_string())
+}
+
+fn current_uid() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+fn current_gid() -> u32 {
+    unsafe { libc::getgid() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mount_options_use_the_mount_fuse_names() {
+        assert_eq!(
+            parse_mount_option("allow_other"),
+            Ok(MountOption::AllowOther)
+        );
+        assert_eq!(
+            parse_mount_option("default_permissions"),
+            Ok(MountOption::DefaultPermissions)
+        );
+        assert_eq!(parse_mount_option("ro"), Ok(MountOption::RO));
+        assert_eq!(
+            parse_mount_option("fsname=bucket"),
+            Ok(MountOption::FSName("bucket".to_string()))
+        );
+        assert_eq!(
+            parse_mount_option("subtype=dalfs"),
+            Ok(MountOption::Subtype("dalfs".to_string()))
+        );
+    }
+
+    #[test]
+    fn unknown_mount_options_pass_through() {
+        assert_eq!(
+            parse_mount_option("max_read=4096"),
+            Ok(MountOption::CUSTOM("max_read=4096".to_string()))
+        );
+        assert_eq!(
+            parse_mount_option("nonempty"),
+            Ok(MountOption::CUSTOM("nonempty".to_string()))
+        );
+        assert!(parse_mount_option("").is_err());
+    }
+
+    #[test]
+    fn modes_are_octal() {
+        assert_eq!(parse_mode("022"), Ok(0o22));
+        assert_eq!(parse_mode("0o755"), Ok(0o755));
+        assert_eq!(parse_mode("7777"), Ok(0o7777));
+        assert!(parse_mode("10000").is_err());
+        assert!(parse_mode("8").is_err());
+        assert!(parse_mode("rwx").is_err());
+        assert!(parse_mode("").is_err());
+    }
+
+    #[test]
+    fn options_are_key_value_pairs() {
+        let options = parse_options("bucket=b,region=r").unwrap();
+        assert_eq!(options["bucket"], "b");
+        assert_eq!(options["region"], "r");
+        assert!(parse_options("bucket").is_err());
+    }
+}
@@ -0,0 +1,580 @@
+use futures::{AsyncReadExt, AsyncSeekExt};
+use opendal::{Error, ErrorKind, Operator, Reader};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::SeekFrom;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Staged writes are kept in memory up to this size, then spilled to disk
+const STAGING_MEMORY_LIMIT: u64 = 8 * 1024 * 1024; // 8 MiB
+const STAGING_CHUNK_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
+
+// Multipart uploads are sent in parts of this size, raised by OpenDAL to the
+// service's minimum part size where it has one (5 MiB on S3)
+const UPLOAD_PART_SIZE: usize = 8 * 1024 * 1024; // 8 MiB
+
+static STAGING_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Local copy of a file being written, uploaded as a whole on commit
+pub enum Staging {
+    Memory(Vec<u8>),
+    File { file: File, len: u64 },
+}
+
+impl Staging {
+    pub fn new() -> Staging {
+        Staging::Memory(Vec::new())
+    }
+
+    pub fn len(&self) -> u64 {
+        match self {
+            Staging::Memory(buffer) => buffer.len() as u64,
+            Staging::File { len, .. } => *len,
+        }
+    }
+
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        let end = offset + data.len() as u64;
+        if let Staging::Memory(buffer) = self {
+            if end <= STAGING_MEMORY_LIMIT {
+                if buffer.len() < end as usize {
+                    buffer.resize(end as usize, 0);
+                }
+                buffer[offset as usize..end as usize].copy_from_slice(data);
+                return Ok(());
+            }
+            self.spill()?;
+        }
+
+        match self {
+            Staging::File { file, len } => {
+                file.write_all_at(data, offset)?;
+                *len = (*len).max(end);
+                Ok(())
+            }
+            Staging::Memory(_) => unreachable!("staging was just spilled to disk"),
+        }
+    }
+
+    pub fn set_len(&mut self, size: u64) -> std::io::Result<()> {
+        if let Staging::Memory(buffer) = self {
+            if size <= STAGING_MEMORY_LIMIT {
+                buffer.resize(size as usize, 0);
+                return Ok(());
+            }
+            self.spill()?;
+        }
+
+        match self {
+            Staging::File { file, len } => {
+                file.set_len(size)?;
+                *len = size;
+                Ok(())
+            }
+            Staging::Memory(_) => unreachable!("staging was just spilled to disk"),
+        }
+    }
+
+    pub fn read_at(&self, offset: u64, end: u64) -> std::io::Result<Vec<u8>> {
+        let end = end.min(self.len());
+        if offset >= end {
+            return Ok(vec![]);
+        }
+
+        match self {
+            Staging::Memory(buffer) => Ok(buffer[offset as usize..end as usize].to_vec()),
+            Staging::File { file, .. } => {
+                let mut buffer = vec![0; (end - offset) as usize];
+                file.read_exact_at(&mut buffer, offset)?;
+                Ok(buffer)
+            }
+        }
+    }
+
+    // Move the in-memory buffer to an anonymous temporary file
+    fn spill(&mut self) -> std::io::Result<()> {
+        let path = std::env::temp_dir().join(format!(
+            "dalfs-{}-{}",
+            std::process::id(),
+            STAGING_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        // Unlink right away so the file goes away with the handle, even on crash
+        std::fs::remove_file(&path)?;
+
+        let len = self.len();
+        if let Staging::Memory(buffer) = self {
+            file.write_all_at(buffer, 0)?;
+        }
+        *self = Staging::File { file, len };
+        Ok(())
+    }
+}
+
+/// Properties set on the object when it is uploaded
+#[derive(Debug, Default)]
+pub struct Properties {
+    pub content_type: Option<String>,
+    pub cache_control: Option<String>,
+    pub content_disposition: Option<String>,
+}
+
+pub struct FileHandle {
+    pub ino: u64,
+    pub flags: i32,
+    pub reader: Option<Reader>,
+    // Offset of the object the reader was opened at
+    pub reader_start: u64,
+    pub reader_offset: u64,
+    pub staging: Option<Staging>,
+    pub dirty: bool,
+}
+
+impl FileHandle {
+    pub fn new(ino: u64, flags: i32) -> FileHandle {
+        FileHandle {
+            ino,
+            flags,
+            reader: None,
+            reader_start: 0,
+            reader_offset: 0,
+            staging: None,
+            dirty: false,
+        }
+    }
+
+    /// Read `offset..end`, from the staged content if the handle has been
+    /// written to, otherwise through the handle's reader, seeking only when
+    /// the request doesn't continue where the previous one stopped
+    ///
+    /// The reader only fetches the object from the first offset read, it is
+    /// opened again when a read goes back before that.
+    pub async fn read(
+        &mut self,
+        op: &Operator,
+        path: &str,
+        offset: u64,
+        end: u64,
+    ) -> opendal::Result<Vec<u8>> {
+        if let Some(ref staging) = self.staging {
+            return staging
+                .read_at(offset, end)
+                .map_err(|err| io_error("read", err));
+        }
+
+        let reader = match self.reader {
+            Some(ref mut reader) if offset >= self.reader_start => reader,
+            _ => {
+                self.reader = None;
+                // Backends refuse a range starting past the end
+                if offset >= op.stat(path).await?.content_length() {
+                    return Ok(vec![]);
+                }
+                self.reader_start = offset;
+                self.reader_offset = offset;
+                self.reader
+                    .insert(op.reader_with(path).range(offset..).await?)
+            }
+        };
+
+        if self.reader_offset != offset {
+            reader
+                .seek(SeekFrom::Start(offset - self.reader_start))
+                .await
+                .map_err(|err| io_error("seek", err))?;
+            self.reader_offset = offset;
+        }
+
+        let mut buffer = vec![0; (end - offset) as usize];
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match reader.read(&mut buffer[filled..]).await {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(err) => {
+                    // The reader state is unknown now, start over on next read
+                    self.reader = None;
+                    return Err(io_error("read", err));
+                }
+            }
+        }
+        buffer.truncate(filled);
+        self.reader_offset += filled as u64;
+
+        Ok(buffer)
+    }
+
+    /// Stage `data` at `offset`, the object is only updated on commit
+    ///
+    /// The first write pulls the current content of a non-empty object into
+    /// the staging area, so that writes at any offset keep the rest intact.
+    pub async fn write(
+        &mut self,
+        op: &Operator,
+        path: &str,
+        size: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> opendal::Result<()> {
+        self.ensure_staging(op, path, size)
+            .await?
+            .write_at(offset, data)
+            .map_err(|err| io_error("write", err))?;
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    /// Shrink or zero-extend the staged content to `new_size`, the object is
+    /// only updated on commit
+    pub async fn truncate(
+        &mut self,
+        op: &Operator,
+        path: &str,
+        size: u64,
+        new_size: u64,
+    ) -> opendal::Result<()> {
+        // Content past the new size is dropped anyway, don't download it
+        self.ensure_staging(op, path, size.min(new_size))
+            .await?
+            .set_len(new_size)
+            .map_err(|err| io_error("truncate", err))?;
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    /// Start from an empty file, as requested by `O_TRUNC`
+    pub fn discard(&mut self) {
+        self.reader = None;
+        self.staging = Some(Staging::new());
+        self.dirty = true;
+    }
+
+    // Staging area of the handle, filled with the first `size` bytes of the
+    // object when it is created
+    async fn ensure_staging(
+        &mut self,
+        op: &Operator,
+        path: &str,
+        size: u64,
+    ) -> opendal::Result<&mut Staging> {
+        if self.staging.is_none() {
+            let mut staging = Staging::new();
+            if size > 0 {
+                load_staging(op, path, size, &mut staging).await?;
+            }
+            self.staging = Some(staging);
+        }
+        Ok(self.staging.as_mut().unwrap())
+    }
+
+    /// Upload the staged content with `properties` if it changed since the
+    /// last commit
+    pub async fn commit(
+        &mut self,
+        op: &Operator,
+        path: &str,
+        properties: &Properties,
+    ) -> opendal::Result<()> {
+        let staging = match self.staging {
+            Some(ref staging) if self.dirty => staging,
+            _ => return Ok(()),
+        };
+
+        let mut future_writer = op.writer_with(path).buffer(UPLOAD_PART_SIZE);
+        if let Some(ref content_type) = properties.content_type {
+            future_writer = future_writer.content_type(content_type);
+        }
+        if let Some(ref cache_control) = properties.cache_control {
+            future_writer = future_writer.cache_control(cache_control);
+        }
+        if let Some(ref content_disposition) = properties.content_disposition {
+            future_writer = future_writer.content_disposition(content_disposition);
+        }
+        let mut writer = future_writer.await?;
+        let mut offset = 0;
+        while offset < staging.len() {
+            let chunk = staging
+                .read_at(offset, offset + STAGING_CHUNK_SIZE as u64)
+                .map_err(|err| io_error("commit", err))?;
+            offset += chunk.len() as u64;
+            if let Err(err) = writer.write(chunk).await {
+                let _ = writer.abort().await;
+                return Err(err);
+            }
+        }
+        writer.close().await?;
+
+        // The object changed underneath the reader
+        self.reader = None;
+        self.dirty = false;
+
+        Ok(())
+    }
+}
+
+/// Read `offset..end` of the object at `path` without a handle, in a single
+/// ranged request
+pub async fn read_range(
+    op: &Operator,
+    path: &str,
+    offset: u64,
+    end: u64,
+) -> opendal::Result<Vec<u8>> {
+    // Backends refuse a range starting past the end
+    let size = op.stat(path).await?.content_length();
+    if offset >= size {
+        return Ok(vec![]);
+    }
+    op.read_with(path).range(offset..end.min(size)).await
+}
+
+async fn load_staging(
+    op: &Operator,
+    path: &str,
+    size: u64,
+    staging: &mut Staging,
+) -> opendal::Result<()> {
+    let mut reader = op.reader_with(path).range(0..size).await?;
+    let mut buffer = vec![0; STAGING_CHUNK_SIZE];
+    loop {
+        let n = reader
+            .read(&mut buffer)
+            .await
+            .map_err(|err| io_error("load", err))?;
+        if n == 0 {
+            return Ok(());
+        }
+        staging
+            .write_at(staging.len(), &buffer[..n])
+            .map_err(|err| io_error("load", err))?;
+    }
+}
+
+fn io_error(operation: &'static str, err: std::io::Error) -> Error {
+    Error::new(ErrorKind::Unexpected, "handle io failed")
+        .with_operation(operation)
+        .set_source(err)
+}
+
+pub struct HandleStore {
+    handle_map: HashMap<u64, FileHandle>,
+    last_fh: u64,
+}
+
+impl HandleStore {
+    pub fn new() -> HandleStore {
+        HandleStore {
+            handle_map: HashMap::new(),
+            last_fh: 0, // 0 is never handed out, so it can't be mistaken for a real handle
+        }
+    }
+
+    pub fn insert(&mut self, handle: FileHandle) -> u64 {
+        self.last_fh += 1;
+        self.handle_map.insert(self.last_fh, handle);
+        self.last_fh
+    }
+
+    pub fn get_mut(&mut self, fh: u64) -> Option<&mut FileHandle> {
+        self.handle_map.get_mut(&fh)
+    }
+
+    pub fn remove(&mut self, fh: u64) -> Option<FileHandle> {
+        self.handle_map.remove(&fh)
+    }
+
+    pub fn for_inode_mut(&mut self, ino: u64) -> impl Iterator<Item = (u64, &mut FileHandle)> {
+        self.handle_map
+            .iter_mut()
+            .filter(move |(_, handle)| handle.ino == ino)
+            .map(|(fh, handle)| (*fh, handle))
+    }
+
+    /// Make the handles of the inodes `affected` holds true for open their
+    /// reader again, a reader sticks to the object as it was when opened
+    pub fn reset_readers<F: Fn(u64) -> bool>(&mut self, affected: F) {
+        for handle in self.handle_map.values_mut() {
+            if affected(handle.ino) {
+                handle.reader = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use opendal::services::Memory;
+
+    fn memory() -> Operator {
+        Operator::new(Memory::default()).unwrap().finish()
+    }
+
+    #[test]
+    fn write_at_fills_gaps_with_zeros() {
+        let mut staging = Staging::new();
+        staging.write_at(0, b"ab").unwrap();
+        staging.write_at(4, b"cd").unwrap();
+        staging.write_at(1, b"x").unwrap();
+
+        assert_eq!(staging.len(), 6);
+        assert_eq!(staging.read_at(0, 6).unwrap(), b"ax\0\0cd");
+        assert_eq!(staging.read_at(4, 100).unwrap(), b"cd");
+        assert!(staging.read_at(6, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn set_len_shrinks_and_extends() {
+        let mut staging = Staging::new();
+        staging.write_at(0, b"hello").unwrap();
+        staging.set_len(2).unwrap();
+        assert_eq!(staging.read_at(0, 10).unwrap(), b"he");
+        staging.set_len(4).unwrap();
+        assert_eq!(staging.read_at(0, 10).unwrap(), b"he\0\0");
+    }
+
+    #[test]
+    fn spills_to_disk_past_the_memory_limit() {
+        let mut staging = Staging::new();
+        staging
+            .write_at(0, &vec![1; STAGING_MEMORY_LIMIT as usize])
+            .unwrap();
+        assert!(matches!(staging, Staging::Memory(_)));
+
+        staging.write_at(STAGING_MEMORY_LIMIT, b"xy").unwrap();
+        assert!(matches!(staging, Staging::File { .. }));
+        assert_eq!(staging.len(), STAGING_MEMORY_LIMIT + 2);
+        let tail = staging
+            .read_at(STAGING_MEMORY_LIMIT - 2, STAGING_MEMORY_LIMIT + 2)
+            .unwrap();
+        assert_eq!(tail, [1, 1, b'x', b'y']);
+
+        staging.set_len(3).unwrap();
+        assert_eq!(staging.len(), 3);
+        assert_eq!(staging.read_at(0, 10).unwrap(), [1, 1, 1]);
+    }
+
+    #[test]
+    fn set_len_past_the_memory_limit_spills() {
+        let mut staging = Staging::new();
+        staging.write_at(0, b"abc").unwrap();
+        staging.set_len(STAGING_MEMORY_LIMIT + 1).unwrap();
+        assert!(matches!(staging, Staging::File { .. }));
+        assert_eq!(staging.read_at(0, 4).unwrap(), b"abc\0");
+    }
+
+    #[test]
+    fn first_write_loads_the_object() {
+        let op = memory();
+        block_on(op.write("file", b"hello world".to_vec())).unwrap();
+
+        let mut handle = FileHandle::new(2, libc::O_WRONLY);
+        block_on(handle.write(&op, "file", 11, 6, b"there")).unwrap();
+        assert!(handle.dirty);
+        let staged = handle.staging.as_ref().unwrap().read_at(0, 100).unwrap();
+        assert_eq!(staged, b"hello there");
+        // Nothing reaches the backend before the commit
+        assert_eq!(block_on(op.read("file")).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn truncate_only_loads_what_is_kept() {
+        let op = memory();
+        block_on(op.write("file", b"hello world".to_vec())).unwrap();
+
+        let mut handle = FileHandle::new(2, libc::O_WRONLY);
+        block_on(handle.truncate(&op, "file", 11, 5)).unwrap();
+        let staged = handle.staging.as_ref().unwrap().read_at(0, 100).unwrap();
+        assert_eq!(staged, b"hello");
+    }
+
+    #[test]
+    fn commit_uploads_once() {
+        let op = memory();
+        let mut handle = FileHandle::new(2, libc::O_WRONLY);
+        block_on(handle.write(&op, "file", 0, 0, b"data")).unwrap();
+        block_on(handle.commit(&op, "file", &Properties::default())).unwrap();
+        assert_eq!(block_on(op.read("file")).unwrap(), b"data");
+        assert!(!handle.dirty);
+
+        // A clean handle leaves the object alone
+        block_on(op.write("file", b"other".to_vec())).unwrap();
+        block_on(handle.commit(&op, "file", &Properties::default())).unwrap();
+        assert_eq!(block_on(op.read("file")).unwrap(), b"other");
+    }
+
+    #[test]
+    fn commit_uploads_content_larger_than_a_part() {
+        let op = memory();
+        let size = UPLOAD_PART_SIZE + STAGING_CHUNK_SIZE + 1;
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        let mut handle = FileHandle::new(2, libc::O_WRONLY);
+        block_on(handle.write(&op, "file", 0, 0, &data)).unwrap();
+        block_on(handle.commit(&op, "file", &Properties::default())).unwrap();
+        assert_eq!(block_on(op.read("file")).unwrap(), data);
+    }
+
+    #[test]
+    fn discard_commits_an_empty_file() {
+        let op = memory();
+        block_on(op.write("file", b"data".to_vec())).unwrap();
+        let mut handle = FileHandle::new(2, libc::O_WRONLY | libc::O_TRUNC);
+        handle.discard();
+        block_on(handle.commit(&op, "file", &Properties::default())).unwrap();
+        assert!(block_on(op.read("file")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_goes_through_the_staged_content() {
+        let op = memory();
+        block_on(op.write("file", b"hello".to_vec())).unwrap();
+        let mut handle = FileHandle::new(2, libc::O_RDWR);
+        assert_eq!(block_on(handle.read(&op, "file", 1, 3)).unwrap(), b"el");
+        // Reading past the end returns what there is
+        assert_eq!(block_on(handle.read(&op, "file", 3, 100)).unwrap(), b"lo");
+
+        block_on(handle.write(&op, "file", 5, 5, b"!")).unwrap();
+        assert_eq!(block_on(handle.read(&op, "file", 3, 100)).unwrap(), b"lo!");
+    }
+
+    #[test]
+    fn reader_starts_at_the_first_offset_read() {
+        let op = memory();
+        block_on(op.write("file", b"hello world".to_vec())).unwrap();
+        let mut handle = FileHandle::new(2, libc::O_RDONLY);
+        assert_eq!(block_on(handle.read(&op, "file", 6, 8)).unwrap(), b"wo");
+        assert_eq!(handle.reader_start, 6);
+        assert_eq!(block_on(handle.read(&op, "file", 9, 20)).unwrap(), b"ld");
+        // Going back opens the reader again
+        assert_eq!(block_on(handle.read(&op, "file", 0, 5)).unwrap(), b"hello");
+        assert_eq!(handle.reader_start, 0);
+        assert!(block_on(handle.read(&op, "file", 11, 20))
+            .unwrap()
+            .is_empty());
+        assert!(
+            block_on(FileHandle::new(2, libc::O_RDONLY).read(&op, "file", 50, 60))
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn read_range_stops_at_the_end() {
+        let op = memory();
+        block_on(op.write("file", b"hello".to_vec())).unwrap();
+        assert_eq!(block_on(read_range(&op, "file", 1, 3)).unwrap(), b"el");
+        assert_eq!(block_on(read_range(&op, "file", 3, 100)).unwrap(), b"lo");
+        assert!(block_on(read_range(&op, "file", 5, 10)).unwrap().is_empty());
+        assert!(block_on(read_range(&op, "file", 50, 60))
+            .unwrap()
+            .is_empty());
+    }
+}
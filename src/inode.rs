@@ -9,39 +9,62 @@ use std::ops::{Index, IndexMut};
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
+use crate::meta::{self, EntryMeta};
+
 #[derive(Debug, Clone)]
 pub struct Inode {
     pub path: PathBuf,
+    // Other paths of a hard-linked file
+    pub links: Vec<PathBuf>,
+    // Object holding the content of a hard-linked file, see DalFs::link
+    pub content: Option<PathBuf>,
     pub attr: FileAttr,
     pub visited: bool,
+    pub updated: SystemTime,
 }
 
 impl Inode {
     pub fn new<P: AsRef<Path>>(path: P, attr: FileAttr) -> Inode {
         Inode {
             path: PathBuf::from(path.as_ref()),
-            attr: attr,
+            links: vec![],
+            content: None,
+            attr,
             visited: false,
+            updated: SystemTime::now(),
         }
     }
+
+    /// Object holding the content and the attributes of the inode
+    pub fn object_path(&self) -> &Path {
+        self.content.as_deref().unwrap_or(&self.path)
+    }
+
+    fn paths(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.path).chain(self.links.iter())
+    }
 }
 
 #[derive(Debug)]
 pub struct InodeStore {
     inode_map: HashMap<u64, Inode>,
     ino_trie: SequenceTrie<OsString, u64>,
+    dir_perm: u16,
+    file_perm: u16,
     uid: u32,
     gid: u32,
     last_ino: u64,
 }
 
 impl InodeStore {
-    pub fn new(perm: u16, uid: u32, gid: u32) -> InodeStore {
+    pub fn new(dir_perm: u16, file_perm: u16, uid: u32, gid: u32) -> InodeStore {
         let mut store = InodeStore {
             inode_map: HashMap::new(),
             ino_trie: SequenceTrie::new(),
-            uid: uid,
-            gid: gid,
+            dir_perm,
+            file_perm,
+            uid,
+            gid,
             last_ino: 1, // 1 is reserved for root
         };
 
@@ -55,10 +78,10 @@ impl InodeStore {
             ctime: now,
             crtime: now,
             kind: FileType::Directory,
-            perm: perm,
-            nlink: 0,
-            uid: uid,
-            gid: gid,
+            perm: dir_perm,
+            nlink: 2,
+            uid,
+            gid,
             rdev: 0,
             flags: 0,
             blksize: 4096,
@@ -77,9 +100,41 @@ impl InodeStore {
         self.ino_trie.get(&sequence).and_then(|ino| self.get(*ino))
     }
 
-    pub fn insert_metadata<P: AsRef<Path>>(&mut self, path: P, metadata: &Metadata) -> &Inode {
+    pub fn default_perm(&self, kind: FileType) -> u16 {
+        match kind {
+            FileType::Directory => self.dir_perm,
+            // Access goes by the target, links themselves are always 0777
+            FileType::Symlink => 0o777,
+            _ => self.file_perm,
+        }
+    }
+
+    pub fn default_owner(&self) -> (u32, u32) {
+        (self.uid, self.gid)
+    }
+
+    /// Build the inode of `path` from its backend metadata, with the
+    /// attributes persisted in `entry_meta` overriding the mount's defaults
+    pub fn insert_metadata<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        metadata: &Metadata,
+        entry_meta: Option<&EntryMeta>,
+    ) -> &Inode {
+        let link = entry_meta
+            .and_then(|meta| meta.link.as_deref())
+            .map(meta::link_path);
         // Non-lexical borrows can't come soon enough
-        let ino_opt = self.get_by_path(path.as_ref()).map(|inode| inode.attr.ino);
+        let ino_opt = self
+            .get_by_path(path.as_ref())
+            .or_else(|| {
+                // Another path of a hard-linked file already known
+                let content = link.as_ref()?;
+                self.inode_map
+                    .values()
+                    .find(|inode| inode.content.as_ref() == Some(content))
+            })
+            .map(|inode| inode.attr.ino);
         let ino = ino_opt.unwrap_or_else(|| {
             self.last_ino += 1;
             self.last_ino
@@ -92,30 +147,61 @@ impl InodeStore {
         if let Some(last_modified_datetime) = metadata.last_modified() {
             ts = last_modified_datetime.into();
         }
+        let kind = match metadata.mode() {
+            EntryMode::FILE => FileType::RegularFile,
+            EntryMode::DIR => FileType::Directory,
+            // TODO: We do not know how to handle it
+            EntryMode::Unknown => FileType::RegularFile,
+        };
+        // Symlinks are objects holding their target, see DalFs::symlink
+        let kind = match entry_meta.is_some_and(|meta| meta.symlink) {
+            true => FileType::Symlink,
+            false => kind,
+        };
+        // Times set through utimens win over what the backend reports
+        let mtime = entry_meta.and_then(|meta| meta.mtime()).unwrap_or(ts);
+        let atime = entry_meta.and_then(|meta| meta.atime()).unwrap_or(mtime);
         let attr = FileAttr {
-            ino: ino,
+            ino,
             size: metadata.content_length(),
-            blocks: 0,
-            atime: ts,
-            mtime: ts,
+            // Counted in 512-byte units, whatever blksize is
+            blocks: metadata.content_length().div_ceil(512),
+            atime,
+            mtime,
             ctime: ts,
             crtime: ts,
-            kind: match metadata.mode() {
-                EntryMode::FILE => FileType::RegularFile,
-                EntryMode::DIR => FileType::Directory,
-                // TODO: We do not know how to handle it
-                EntryMode::Unknown => FileType::RegularFile,
-            },
-            perm: 0o550,
-            nlink: 0,
-            uid: self.uid,
-            gid: self.gid,
+            kind,
+            perm: entry_meta
+                .and_then(|meta| meta.mode)
+                .unwrap_or(self.default_perm(kind)),
+            // Subdirectories aren't counted, tools take 2 as "unknown"
+            nlink: entry_meta
+                .and_then(|meta| meta.nlink)
+                .unwrap_or(match kind {
+                    FileType::Directory => 2,
+                    _ => 1,
+                }),
+            uid: entry_meta.and_then(|meta| meta.uid).unwrap_or(self.uid),
+            gid: entry_meta.and_then(|meta| meta.gid).unwrap_or(self.gid),
             rdev: 0,
             flags: 0,
             blksize: 4096,
         };
 
-        self.insert(Inode::new(path, attr));
+        // A known inode keeps its state, such as whether it has been listed
+        match self.inode_map.get_mut(&ino) {
+            Some(inode) => {
+                let known = inode.paths().any(|known| known == path.as_ref());
+                if !known {
+                    inode.links.push(path.as_ref().to_path_buf());
+                    self.ino_trie.insert(&path_to_sequence(path.as_ref()), ino);
+                }
+                inode.attr = attr;
+                inode.content = link.or(inode.content.take());
+                inode.updated = SystemTime::now();
+            }
+            None => self.insert(Inode::new(path, attr)),
+        }
         self.get(ino).unwrap()
     }
 
@@ -127,7 +213,9 @@ impl InodeStore {
         })
     }
 
-    pub fn children(&self, ino: u64) -> Vec<&Inode> {
+    /// Names and inodes of the entries of a directory, a hard-linked file
+    /// shows up once per name it has there
+    pub fn children(&self, ino: u64) -> Vec<(&OsString, &Inode)> {
         match self.get(ino) {
             Some(inode) => {
                 let sequence = path_to_sequence(&inode.path);
@@ -135,12 +223,14 @@ impl InodeStore {
                     .ino_trie
                     .get_node(&sequence)
                     .expect("inconsistent fs - failed to lookup by path after lookup by ino");
-                node.children()
-                    .iter()
-                    .filter_map(|c| c.value())
-                    .map(|ino| {
-                        self.get(*ino)
-                            .expect("inconsistent fs - found child without inode")
+                node.children_with_keys()
+                    .into_iter()
+                    .filter_map(|(name, c)| c.value().map(|ino| (name, ino)))
+                    .map(|(name, ino)| {
+                        let child = self
+                            .get(*ino)
+                            .expect("inconsistent fs - found child without inode");
+                        (name, child)
                     })
                     .collect()
             }
@@ -197,42 +287,129 @@ impl InodeStore {
         }
 
         if self.ino_trie.insert(&sequence, ino).is_some() {
-            let node = self.ino_trie.get_node_mut(&sequence).expect(&format!(
-                "Corrupt inode store: couldn't insert or modify ino_trie at {:?}",
-                &sequence
-            ));
+            let node = self.ino_trie.get_node_mut(&sequence).unwrap_or_else(|| {
+                panic!(
+                    "Corrupt inode store: couldn't insert or modify ino_trie at {:?}",
+                    &sequence
+                )
+            });
             // TODO: figure out why this check triggers a false alarm panic on backspacing to dir and then tabbing
             // if node.value.is_some() {
             //     panic!("Corrupt inode store: reinserted ino {} into ino_trie, prev value: {}", ino, node.value.unwrap());
             // }
-            node.value_mut().map(|v| *v = ino);
+            if let Some(v) = node.value_mut() {
+                *v = ino;
+            }
+        }
+    }
+
+    /// Move the inode at `from` and every inode below it to `to`, keeping
+    /// their inode numbers
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let moved: Vec<(u64, PathBuf)> = self
+            .inode_map
+            .values()
+            .flat_map(|inode| {
+                inode
+                    .paths()
+                    .filter(|path| path.starts_with(from))
+                    .map(|path| (inode.attr.ino, path.clone()))
+            })
+            .collect();
+
+        for (_, path) in &moved {
+            self.ino_trie.remove(&path_to_sequence(path));
         }
+        for (ino, old_path) in moved {
+            let relative = old_path.strip_prefix(from).unwrap();
+            let new_path = match relative.as_os_str().is_empty() {
+                true => to.to_path_buf(),
+                false => to.join(relative),
+            };
+            let inode = self.inode_map.get_mut(&ino).unwrap();
+            match inode.links.iter_mut().find(|link| **link == old_path) {
+                Some(link) => *link = new_path.clone(),
+                None => inode.path = new_path.clone(),
+            }
+            log::debug!("Moving ino {} to path {}", ino, new_path.display());
+            self.ino_trie.insert(&path_to_sequence(&new_path), ino);
+        }
+    }
+
+    /// Give the inode of a hard-linked file another path
+    pub fn link<P: AsRef<Path>>(&mut self, ino: u64, path: P) {
+        self.inode_map
+            .get_mut(&ino)
+            .unwrap()
+            .links
+            .push(path.as_ref().to_path_buf());
+        self.ino_trie.insert(&path_to_sequence(path.as_ref()), ino);
     }
 
+    /// Drop one path of the inode, the inode goes away with its last path
+    pub fn unlink<P: AsRef<Path>>(&mut self, ino: u64, path: P) {
+        let path = path.as_ref();
+        let inode = self.inode_map.get_mut(&ino).unwrap();
+        if inode.links.is_empty() {
+            return self.remove(ino);
+        }
+        match inode.path == path {
+            true => inode.path = inode.links.remove(0),
+            false => inode.links.retain(|link| link != path),
+        }
+        self.ino_trie.remove(&path_to_sequence(path));
+    }
+
+    /// Forget the inode along with everything cached below its paths, which
+    /// went away with it
     pub fn remove(&mut self, ino: u64) {
-        let sequence = {
-            let ref path = self.inode_map[&ino].path;
-            path_to_sequence(&path)
-        };
+        let removed: Vec<PathBuf> = self.inode_map[&ino].paths().cloned().collect();
+        let below: Vec<(u64, PathBuf)> = self
+            .inode_map
+            .values()
+            .flat_map(|inode| {
+                inode
+                    .paths()
+                    .filter(|path| removed.iter().any(|dir| path.starts_with(dir)))
+                    .map(|path| (inode.attr.ino, path.clone()))
+            })
+            .collect();
 
         self.inode_map.remove(&ino);
-        self.ino_trie.remove(&sequence);
+        for (child, path) in &below {
+            self.ino_trie.remove(&path_to_sequence(path));
+            // Hard-linked files may live on under another path
+            if let Some(inode) = self.inode_map.get_mut(child) {
+                inode.links.retain(|link| link != path);
+                if inode.path == *path {
+                    match inode.links.is_empty() {
+                        true => {
+                            self.inode_map.remove(child);
+                        }
+                        false => inode.path = inode.links.remove(0),
+                    }
+                }
+            }
+        }
 
-        assert!(self.inode_map.get(&ino).is_none());
-        assert!(self.ino_trie.get(&sequence).is_none());
+        assert!(!self.inode_map.contains_key(&ino));
+        assert!(below
+            .iter()
+            .all(|(_, path)| self.ino_trie.get(&path_to_sequence(path)).is_none()));
     }
 }
 
 impl Index<u64> for InodeStore {
     type Output = Inode;
 
-    fn index<'a>(&'a self, index: u64) -> &'a Inode {
+    fn index(&self, index: u64) -> &Inode {
         self.get(index).unwrap()
     }
 }
 
 impl IndexMut<u64> for InodeStore {
-    fn index_mut<'a>(&'a mut self, index: u64) -> &'a mut Inode {
+    fn index_mut(&mut self, index: u64) -> &mut Inode {
         self.get_mut(index).unwrap()
     }
 }
@@ -240,3 +417,136 @@ impl IndexMut<u64> for InodeStore {
 fn path_to_sequence(path: &Path) -> Vec<OsString> {
     path.iter().map(|s| s.to_owned()).collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> InodeStore {
+        InodeStore::new(0o755, 0o644, 1000, 1000)
+    }
+
+    fn metadata(mode: EntryMode) -> Metadata {
+        let mut metadata = Metadata::new(mode);
+        metadata.set_content_length(0);
+        metadata.set_last_modified(chrono::Utc::now());
+        metadata
+    }
+
+    fn insert(store: &mut InodeStore, path: &str, mode: EntryMode) -> u64 {
+        store.insert_metadata(path, &metadata(mode), None).attr.ino
+    }
+
+    #[test]
+    fn remove_forgets_the_whole_subtree() {
+        let mut store = store();
+        let dir = insert(&mut store, "/a", EntryMode::DIR);
+        let sub = insert(&mut store, "/a/b", EntryMode::DIR);
+        let file = insert(&mut store, "/a/b/c", EntryMode::FILE);
+        let sibling = insert(&mut store, "/ab", EntryMode::FILE);
+
+        store.remove(dir);
+
+        for ino in [dir, sub, file] {
+            assert!(store.get(ino).is_none());
+        }
+        assert!(store.get_by_path("/a/b/c").is_none());
+        assert!(store.child(1, "a").is_none());
+        assert_eq!(store.get_by_path("/ab").unwrap().attr.ino, sibling);
+        let names: Vec<_> = store
+            .children(1)
+            .into_iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        assert_eq!(names, ["ab"]);
+    }
+
+    #[test]
+    fn remove_keeps_links_outside_the_subtree() {
+        let mut store = store();
+        let dir = insert(&mut store, "/a", EntryMode::DIR);
+        let file = insert(&mut store, "/a/f", EntryMode::FILE);
+        store.link(file, "/g");
+
+        store.remove(dir);
+
+        let inode = store.get(file).unwrap();
+        assert_eq!(inode.path, Path::new("/g"));
+        assert!(inode.links.is_empty());
+        assert!(store.get_by_path("/a/f").is_none());
+    }
+
+    #[test]
+    fn refresh_keeps_the_inode_state() {
+        let mut store = store();
+        let dir = insert(&mut store, "/a", EntryMode::DIR);
+        store[dir].visited = true;
+
+        let mut metadata = metadata(EntryMode::DIR);
+        metadata.set_content_length(7);
+        store.insert_metadata("/a", &metadata, None);
+
+        assert!(store[dir].visited);
+        assert_eq!(store[dir].attr.size, 7);
+        assert_eq!(insert(&mut store, "/a", EntryMode::DIR), dir);
+    }
+
+    #[test]
+    fn rename_moves_the_subtree_and_keeps_inode_numbers() {
+        let mut store = store();
+        let dir = insert(&mut store, "/a", EntryMode::DIR);
+        let file = insert(&mut store, "/a/f", EntryMode::FILE);
+        let other = insert(&mut store, "/ab", EntryMode::FILE);
+
+        store.rename("/a", "/b/c");
+
+        assert_eq!(store[dir].path, Path::new("/b/c"));
+        assert_eq!(store[file].path, Path::new("/b/c/f"));
+        assert_eq!(store.get_by_path("/b/c/f").unwrap().attr.ino, file);
+        assert!(store.get_by_path("/a").is_none());
+        assert!(store.get_by_path("/a/f").is_none());
+        assert_eq!(store[other].path, Path::new("/ab"));
+    }
+
+    #[test]
+    fn rename_moves_single_links() {
+        let mut store = store();
+        let file = insert(&mut store, "/f", EntryMode::FILE);
+        store.link(file, "/g");
+
+        store.rename("/g", "/h");
+
+        assert_eq!(store[file].path, Path::new("/f"));
+        assert_eq!(store[file].links, [PathBuf::from("/h")]);
+        assert_eq!(store.get_by_path("/h").unwrap().attr.ino, file);
+        assert!(store.get_by_path("/g").is_none());
+    }
+
+    #[test]
+    fn links_share_the_inode_until_the_last_one_goes() {
+        let mut store = store();
+        let file = insert(&mut store, "/f", EntryMode::FILE);
+        store.link(file, "/g");
+        assert_eq!(store.child(1, "g").unwrap().attr.ino, file);
+        assert_eq!(store.children(1).len(), 2);
+
+        store.unlink(file, "/f");
+        assert_eq!(store[file].path, Path::new("/g"));
+        assert!(store[file].links.is_empty());
+        assert!(store.get_by_path("/f").is_none());
+
+        store.unlink(file, "/g");
+        assert!(store.get(file).is_none());
+        assert!(store.children(1).is_empty());
+    }
+
+    #[test]
+    fn new_inodes_get_the_default_attributes() {
+        let mut store = store();
+        let dir = insert(&mut store, "/d", EntryMode::DIR);
+        let file = insert(&mut store, "/f", EntryMode::FILE);
+        assert_eq!(store[dir].attr.perm, 0o755);
+        assert_eq!(store[file].attr.perm, 0o644);
+        assert_eq!((store[file].attr.uid, store[file].attr.gid), (1000, 1000));
+    }
+}
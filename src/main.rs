@@ -1,21 +1,25 @@
 use clap::Parser;
 use config::App;
-use fuser::Session;
+use fuser::{MountOption, Session};
 use opendal::Operator;
 use tap::{Pipe, Tap};
 use tokio::{
-    runtime::{self, Runtime},
+    runtime,
     signal::unix::{signal, SignalKind},
     task::spawn_blocking,
 };
 
 use std::process::ExitCode;
+use std::time::Duration;
 
-use log;
-
+mod access;
+mod acl;
 mod config;
 mod dalfs;
+mod handle;
 mod inode;
+mod meta;
+mod xattr;
 
 fn main() -> ExitCode {
     let config = config::App::parse();
@@ -25,7 +29,8 @@ fn main() -> ExitCode {
         .enable_io()
         .build()
         .expect("failed to build tokio runtime")
-        .block_on(run(config)) {
+        .block_on(run(config))
+    {
         log::error!("{e}");
         return ExitCode::FAILURE;
     }
@@ -35,13 +40,42 @@ fn main() -> ExitCode {
 
 async fn run(config: App) -> Result<(), Box<dyn std::error::Error>> {
     let options = config.options.unwrap_or_default();
+    let fsname = format!("dalfs:{}", config.r#type);
 
-    let fs = dalfs::DalFs {
-        op: Operator::via_map(config.r#type, options)?.tap(|op| log::debug!("operator: {op:?}")),
-        inodes: inode::InodeStore::new(0o550, 1000, 1000), // Temporarilly hardcode
-    };
+    let fs = dalfs::DalFs::new(
+        Operator::via_map(config.r#type, options)?.tap(|op| log::debug!("operator: {op:?}")),
+        inode::InodeStore::new(
+            config.dir_mode & !config.umask,
+            config.file_mode & !config.umask,
+            config.uid,
+            config.gid,
+        ),
+        config.read_only || config.mount_options.contains(&MountOption::RO),
+        config
+            .mount_options
+            .contains(&MountOption::DefaultPermissions),
+        config.hard_links,
+        Duration::from_secs(config.presign_expiry),
+    );
+
+    let mut mount_options = config.mount_options;
+    if !mount_options
+        .iter()
+        .any(|option| matches!(option, MountOption::FSName(_)))
+    {
+        mount_options.push(MountOption::FSName(fsname));
+    }
+    if !mount_options
+        .iter()
+        .any(|option| matches!(option, MountOption::Subtype(_)))
+    {
+        mount_options.push(MountOption::Subtype("dalfs".to_string()));
+    }
+    if fs.read_only && !mount_options.contains(&MountOption::RO) {
+        mount_options.push(MountOption::RO);
+    }
 
-    let mut session = Session::new(fs, config.mount_point.as_ref(), &[])?;
+    let mut session = Session::new(fs, config.mount_point.as_ref(), &mount_options)?;
     let mut umounter = session.unmount_callable();
     let session_task = spawn_blocking(move || session.run());
 
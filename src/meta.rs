@@ -0,0 +1,345 @@
+use opendal::{ErrorKind, Operator};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the hidden object holding the attributes of a directory's entries
+pub const META_NAME: &str = ".dalfs-meta";
+/// Name of the hidden root directory holding the content of hard-linked files
+pub const LINKS_DIR: &str = ".dalfs-links";
+// Key of the record of the directory itself, which only the root needs as it
+// has no parent to hold it
+const SELF_NAME: &str = ".";
+// Sidecars kept in memory, the oldest go first
+const CACHED_DIRS: usize = 1024;
+
+/// POSIX attributes OpenDAL has no place for, all optional so that a missing
+/// one falls back to the mount's defaults
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EntryMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    // Nanoseconds since the epoch, negative before it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atime: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    // The object holds the target of a symlink
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub symlink: bool,
+    // The object is a placeholder for a hard link to the content object of
+    // this id, whose own record holds the attributes shared by all links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nlink: Option<u32>,
+    // Extended attributes set through setxattr, by full name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl EntryMeta {
+    pub fn atime(&self) -> Option<SystemTime> {
+        self.atime.map(from_timestamp)
+    }
+
+    pub fn set_atime(&mut self, atime: SystemTime) {
+        self.atime = Some(to_timestamp(atime));
+    }
+
+    pub fn mtime(&self) -> Option<SystemTime> {
+        self.mtime.map(from_timestamp)
+    }
+
+    pub fn set_mtime(&mut self, mtime: SystemTime) {
+        self.mtime = Some(to_timestamp(mtime));
+    }
+}
+
+/// Path of the object holding the content of the hard-linked file `id`
+pub fn link_path(id: &str) -> PathBuf {
+    Path::new("/").join(LINKS_DIR).join(id)
+}
+
+fn to_timestamp(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i64,
+        Err(err) => -(err.duration().as_nanos() as i64),
+    }
+}
+
+fn from_timestamp(timestamp: i64) -> SystemTime {
+    let offset = Duration::from_nanos(timestamp.unsigned_abs());
+    match timestamp >= 0 {
+        true => UNIX_EPOCH + offset,
+        false => UNIX_EPOCH - offset,
+    }
+}
+
+type DirMeta = BTreeMap<String, EntryMeta>;
+
+/// Sidecar store keeping an `EntryMeta` per entry in a `.dalfs-meta` object
+/// inside each directory of the backend
+///
+/// A change rewrites the whole sidecar of the directory. It starts from the
+/// stored sidecar rather than the cached one so that records other mounts
+/// changed meanwhile survive, but two mounts changing the same directory at
+/// once still race.
+pub struct MetaStore {
+    op: Operator,
+    dirs: HashMap<PathBuf, DirMeta>,
+    loaded: VecDeque<PathBuf>,
+}
+
+impl MetaStore {
+    pub fn new(op: Operator) -> MetaStore {
+        MetaStore {
+            op,
+            dirs: HashMap::new(),
+            loaded: VecDeque::new(),
+        }
+    }
+
+    pub async fn get<P: AsRef<Path>>(&mut self, path: P) -> opendal::Result<Option<EntryMeta>> {
+        let (dir, name) = match split_path(path.as_ref()) {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+        Ok(self.load(dir).await?.get(name).cloned())
+    }
+
+    /// Change the attributes of `path` and write its directory's sidecar back
+    /// if they changed
+    pub async fn update<P, F>(&mut self, path: P, f: F) -> opendal::Result<()>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut EntryMeta),
+    {
+        let (dir, name) = match split_path(path.as_ref()) {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        let cached = self.load(dir).await?.get(name).cloned();
+        let mut entry = cached.clone().unwrap_or_default();
+        f(&mut entry);
+        let entry = Some(entry).filter(|entry| *entry != EntryMeta::default());
+        if entry == cached {
+            return Ok(());
+        }
+
+        let mut dir_meta = self.fetch(dir).await?;
+        match entry {
+            Some(entry) => dir_meta.insert(name.to_string(), entry),
+            None => dir_meta.remove(name),
+        };
+        self.store(dir, dir_meta).await
+    }
+
+    pub async fn remove<P: AsRef<Path>>(&mut self, path: P) -> opendal::Result<()> {
+        self.update(path, |meta| *meta = EntryMeta::default()).await
+    }
+
+    /// Carry the attributes of `from` over to `to`, replacing those of `to`
+    pub async fn rename<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        from: P,
+        to: Q,
+    ) -> opendal::Result<()> {
+        let entry = self.get(from.as_ref()).await?;
+        self.remove(from.as_ref()).await?;
+        self.update(to, |meta| *meta = entry.unwrap_or_default())
+            .await
+    }
+
+    /// Forget the cached sidecars of `dir` and its subdirectories, e.g. when
+    /// it is listed again or moved
+    pub fn invalidate<P: AsRef<Path>>(&mut self, dir: P) {
+        self.dirs
+            .retain(|cached, _| !cached.starts_with(dir.as_ref()));
+        self.loaded
+            .retain(|cached| !cached.starts_with(dir.as_ref()));
+    }
+
+    async fn load(&mut self, dir: &Path) -> opendal::Result<&DirMeta> {
+        if !self.dirs.contains_key(dir) {
+            let dir_meta = self.fetch(dir).await?;
+            self.cache(dir, dir_meta);
+        }
+        Ok(&self.dirs[dir])
+    }
+
+    // The sidecar of `dir` as stored in the backend
+    async fn fetch(&self, dir: &Path) -> opendal::Result<DirMeta> {
+        let sidecar = dir.join(META_NAME);
+        match self.op.read(sidecar.to_str().unwrap()).await {
+            Ok(buffer) => Ok(serde_json::from_slice(&buffer).unwrap_or_else(|err| {
+                log::warn!("Ignoring corrupted {}: {}", sidecar.display(), err);
+                DirMeta::new()
+            })),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(DirMeta::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn store(&mut self, dir: &Path, dir_meta: DirMeta) -> opendal::Result<()> {
+        let sidecar = dir.join(META_NAME);
+        let sidecar = sidecar.to_str().unwrap();
+        if dir_meta.is_empty() {
+            match self.op.delete(sidecar).await {
+                Err(err) if err.kind() != ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        } else {
+            let buffer = serde_json::to_vec(&dir_meta).expect("failed to serialize metadata");
+            self.op.write(sidecar, buffer).await?;
+        }
+        self.cache(dir, dir_meta);
+        Ok(())
+    }
+
+    fn cache(&mut self, dir: &Path, dir_meta: DirMeta) {
+        if self.dirs.insert(dir.to_path_buf(), dir_meta).is_none() {
+            self.loaded.push_back(dir.to_path_buf());
+        }
+        while self.loaded.len() > CACHED_DIRS {
+            if let Some(oldest) = self.loaded.pop_front() {
+                self.dirs.remove(&oldest);
+            }
+        }
+    }
+}
+
+// Directory and entry name of a path, the root is an entry of its own
+// directory, None for non UTF-8 names
+fn split_path(path: &Path) -> Option<(&Path, &str)> {
+    match path.parent() {
+        Some(dir) => Some((dir, path.file_name()?.to_str()?)),
+        None => Some((path, SELF_NAME)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use opendal::services::Memory;
+
+    fn store() -> (Operator, MetaStore) {
+        let op = Operator::new(Memory::default()).unwrap().finish();
+        (op.clone(), MetaStore::new(op))
+    }
+
+    fn mode(mode: u16) -> impl FnOnce(&mut EntryMeta) {
+        move |meta| meta.mode = Some(mode)
+    }
+
+    #[test]
+    fn default_records_write_nothing() {
+        let (op, mut meta) = store();
+        block_on(meta.update("/d/f", |_| {})).unwrap();
+        block_on(meta.remove("/d/f")).unwrap();
+        assert!(!block_on(op.is_exist("/d/.dalfs-meta")).unwrap());
+    }
+
+    #[test]
+    fn records_round_trip_through_the_sidecar() {
+        let (op, mut meta) = store();
+        block_on(meta.update("/d/f", mode(0o600))).unwrap();
+        assert!(block_on(op.is_exist("/d/.dalfs-meta")).unwrap());
+
+        let mut other = MetaStore::new(op.clone());
+        let entry = block_on(other.get("/d/f")).unwrap().unwrap();
+        assert_eq!(entry.mode, Some(0o600));
+
+        block_on(meta.remove("/d/f")).unwrap();
+        assert!(!block_on(op.is_exist("/d/.dalfs-meta")).unwrap());
+    }
+
+    #[test]
+    fn removing_a_vanished_sidecar_succeeds() {
+        let (op, mut meta) = store();
+        block_on(meta.update("/d/f", mode(0o600))).unwrap();
+        block_on(op.delete("/d/.dalfs-meta")).unwrap();
+        block_on(meta.remove("/d/f")).unwrap();
+    }
+
+    #[test]
+    fn updates_keep_records_of_other_mounts() {
+        let (op, mut meta) = store();
+        let mut other = MetaStore::new(op.clone());
+        block_on(meta.get("/d/f")).unwrap();
+        block_on(other.update("/d/g", mode(0o600))).unwrap();
+        block_on(meta.update("/d/f", mode(0o700))).unwrap();
+
+        let mut fresh = MetaStore::new(op);
+        assert_eq!(
+            block_on(fresh.get("/d/g")).unwrap().unwrap().mode,
+            Some(0o600)
+        );
+        assert_eq!(
+            block_on(fresh.get("/d/f")).unwrap().unwrap().mode,
+            Some(0o700)
+        );
+    }
+
+    #[test]
+    fn rename_moves_the_record() {
+        let (op, mut meta) = store();
+        block_on(meta.update("/d/f", mode(0o600))).unwrap();
+        block_on(meta.update("/e/g", mode(0o700))).unwrap();
+        block_on(meta.rename("/d/f", "/e/g")).unwrap();
+
+        let mut fresh = MetaStore::new(op);
+        assert_eq!(block_on(fresh.get("/d/f")).unwrap(), None);
+        assert_eq!(
+            block_on(fresh.get("/e/g")).unwrap().unwrap().mode,
+            Some(0o600)
+        );
+    }
+
+    #[test]
+    fn root_has_a_record() {
+        let (op, mut meta) = store();
+        block_on(meta.update("/", mode(0o700))).unwrap();
+        block_on(meta.update("/f", mode(0o600))).unwrap();
+
+        let mut fresh = MetaStore::new(op);
+        assert_eq!(block_on(fresh.get("/")).unwrap().unwrap().mode, Some(0o700));
+        assert_eq!(
+            block_on(fresh.get("/f")).unwrap().unwrap().mode,
+            Some(0o600)
+        );
+    }
+
+    #[test]
+    fn cache_is_bounded() {
+        let (_, mut meta) = store();
+        for i in 0..CACHED_DIRS + 10 {
+            block_on(meta.get(format!("/{}/f", i))).unwrap();
+        }
+        assert_eq!(meta.dirs.len(), CACHED_DIRS);
+        assert_eq!(meta.loaded.len(), CACHED_DIRS);
+        assert!(!meta.dirs.contains_key(Path::new("/0")));
+
+        meta.invalidate("/");
+        assert!(meta.dirs.is_empty() && meta.loaded.is_empty());
+    }
+
+    #[test]
+    fn timestamps_before_the_epoch() {
+        let mut entry = EntryMeta::default();
+        let time = UNIX_EPOCH - Duration::from_nanos(1_500);
+        entry.set_mtime(time);
+        assert_eq!(entry.mtime, Some(-1_500));
+        assert_eq!(entry.mtime(), Some(time));
+    }
+}
@@ -0,0 +1,66 @@
+use fuser::ReplyXattr;
+use libc::ERANGE;
+use opendal::Metadata;
+
+/// Namespace of the attributes DalFs derives from the backend
+pub const DALFS_PREFIX: &str = "user.dalfs.";
+/// Attributes of that namespace that can be set, as object properties
+/// applied on the next upload
+pub const CONTENT_TYPE: &str = "user.dalfs.content_type";
+pub const CACHE_CONTROL: &str = "user.dalfs.cache_control";
+pub const CONTENT_DISPOSITION: &str = "user.dalfs.content_disposition";
+/// Virtual attribute returning a presigned URL to download the object
+pub const PRESIGN_READ: &str = "user.dalfs.presign_read";
+/// Namespace of the attributes users can set freely
+pub const USER_PREFIX: &str = "user.";
+
+/// Whether `name` is one of the object properties that can be set
+pub fn is_property(name: &str) -> bool {
+    [CONTENT_TYPE, CACHE_CONTROL, CONTENT_DISPOSITION].contains(&name)
+}
+
+/// Read-only attributes with what the backend reports about an object, from
+/// the complete metadata returned by `stat`
+pub fn from_metadata(metadata: &Metadata) -> Vec<(String, Vec<u8>)> {
+    let fields = [
+        ("etag", metadata.etag().map(str::to_string)),
+        ("content_type", metadata.content_type().map(str::to_string)),
+        ("content_md5", metadata.content_md5().map(str::to_string)),
+        ("version", metadata.version().map(str::to_string)),
+        (
+            "last_modified",
+            metadata.last_modified().map(|time| time.to_rfc3339()),
+        ),
+        (
+            "cache_control",
+            metadata.cache_control().map(str::to_string),
+        ),
+        (
+            "content_disposition",
+            metadata.content_disposition().map(str::to_string),
+        ),
+    ];
+    fields
+        .into_iter()
+        .filter_map(|(name, value)| {
+            Some((format!("{}{}", DALFS_PREFIX, name), value?.into_bytes()))
+        })
+        .collect()
+}
+
+/// Names as listxattr returns them, each one terminated by a NUL
+pub fn list<'a>(names: impl Iterator<Item = &'a str>) -> Vec<u8> {
+    names.flat_map(|name| name.bytes().chain([0])).collect()
+}
+
+/// Reply with the size of `value` when asked for it with a `size` of 0, with
+/// ERANGE when it doesn't fit in `size`, otherwise with `value` itself
+pub fn reply(reply: ReplyXattr, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
+    }
+}